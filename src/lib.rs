@@ -23,8 +23,24 @@ fn sync() -> PyResult<()> {
     Ok(())
 }
 
+/// Search synced messages by author/message text without a server round-trip.
+/// Returns matching uuids ordered best match first, capped at `limit`.
+#[pyfunction]
+fn search(query: String, limit: usize) -> PyResult<Vec<String>> {
+    Ok(magic::search(query, limit))
+}
+
+/// Reassemble a row's `image` column (as read from `results.csv`) back into
+/// the payload it was chunked from, or `None` if the row has no image.
+#[pyfunction]
+fn get_image(manifest: String) -> PyResult<Option<String>> {
+    Ok(magic::get_image(manifest))
+}
+
 #[pymodule]
 fn app(_py: Python, m: &PyModule) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(sync, m)?)?;
+    m.add_function(wrap_pyfunction!(search, m)?)?;
+    m.add_function(wrap_pyfunction!(get_image, m)?)?;
     Ok(())
 }
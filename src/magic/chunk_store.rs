@@ -0,0 +1,136 @@
+//! A content-defined chunk store for image payloads.
+//!
+//! `image` payloads are likely large base64 blobs that otherwise get copied
+//! verbatim into every row that carries them and rewritten on every merge.
+//! Splitting them into FastCDC content-defined chunks and storing each chunk
+//! once under its content hash means a re-sync only has to write the chunks
+//! that actually changed; a row's `image` column becomes a manifest (its
+//! chunk ids, joined by `:`) instead of the raw payload.
+
+use std::path::Path;
+
+const CHUNK_STORE_DIR: &str = "chunk_store";
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+const AVG_CHUNK_SIZE: usize = 8 * 1024;
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+// normalized chunking: a stricter (more bits) mask below the average target
+// size discourages cutting too early, and a looser (fewer bits) mask above
+// it encourages cutting before `MAX_CHUNK_SIZE`, so boundaries cluster
+// around the average instead of following a long exponential tail.
+const MASK_SMALL: u64 = 0x0000_3FFF_C000_0000; // 16 one-bits
+const MASK_LARGE: u64 = 0x0000_0007_FF80_0000; // 12 one-bits
+
+/// `gear[256]`, a table of fixed pseudo-random u64s used to roll the
+/// gear-hash fingerprint a byte at a time: `fp = (fp << 1) + gear[byte]`.
+/// Generated once at compile time with splitmix64 so the table is fixed
+/// without checking in 2KB of hex literals.
+const GEAR: [u64; 256] = {
+    const fn splitmix64(seed: u64) -> (u64, u64) {
+        let seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31), seed)
+    }
+    let mut table = [0u64; 256];
+    let mut state = 0x1234_5678_9ABC_DEF0u64;
+    let mut i = 0;
+    while i < 256 {
+        let (value, next_state) = splitmix64(state);
+        table[i] = value;
+        state = next_state;
+        i += 1;
+    }
+    table
+};
+
+fn chunk_path(id: &str) -> String {
+    format!("{CHUNK_STORE_DIR}/{id}")
+}
+
+fn chunk_id(chunk: &[u8]) -> String {
+    blake3::hash(chunk).to_hex().to_string()
+}
+
+/// Split `data` into content-defined chunks: a boundary falls wherever the
+/// rolling gear-hash fingerprint matches the mask for the current chunk
+/// length, bounded by `MIN_CHUNK_SIZE`/`MAX_CHUNK_SIZE` so a pathological
+/// input can't produce a zero-length or unbounded chunk.
+fn fastcdc_chunk_boundaries(data: &[u8]) -> Vec<&[u8]> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        let remaining = &data[start..];
+        if remaining.len() <= MIN_CHUNK_SIZE {
+            chunks.push(remaining);
+            break;
+        }
+
+        let mut fp: u64 = 0;
+        let mut boundary = remaining.len().min(MAX_CHUNK_SIZE);
+        for (i, &byte) in remaining.iter().enumerate().take(MAX_CHUNK_SIZE) {
+            let len = i + 1;
+            fp = (fp << 1).wrapping_add(GEAR[byte as usize]);
+            if len < MIN_CHUNK_SIZE {
+                continue;
+            }
+            let mask = if len < AVG_CHUNK_SIZE {
+                MASK_SMALL
+            } else {
+                MASK_LARGE
+            };
+            if fp & mask == 0 {
+                boundary = len;
+                break;
+            }
+        }
+
+        chunks.push(&remaining[..boundary]);
+        start += boundary;
+    }
+
+    chunks
+}
+
+/// Store `data` as content-addressed chunks under `CHUNK_STORE_DIR`,
+/// writing only the chunks whose content id isn't already known, and
+/// return the manifest (chunk ids, in order) needed to reconstruct it.
+fn store_chunks(data: &[u8]) -> Vec<String> {
+    std::fs::create_dir_all(CHUNK_STORE_DIR).unwrap();
+    fastcdc_chunk_boundaries(data)
+        .into_iter()
+        .map(|chunk| {
+            let id = chunk_id(chunk);
+            let path = chunk_path(&id);
+            if !Path::new(&path).exists() {
+                std::fs::write(&path, chunk).unwrap();
+            }
+            id
+        })
+        .collect()
+}
+
+/// Encode an `image` payload as its chunk manifest, deduplicating against
+/// chunks already in the store. An absent image encodes as an empty string.
+pub(crate) fn encode_image_manifest(image: Option<&str>) -> String {
+    match image {
+        None => String::new(),
+        Some(image) => store_chunks(image.as_bytes()).join(":"),
+    }
+}
+
+/// Reassemble an `image` payload from its chunk manifest, the inverse of
+/// `encode_image_manifest`: split the manifest on `:` and concatenate each
+/// chunk's bytes back from `CHUNK_STORE_DIR` in order. An empty manifest (no
+/// image) decodes to `None`.
+pub(crate) fn decode_image_manifest(manifest: &str) -> Option<String> {
+    if manifest.is_empty() {
+        return None;
+    }
+    let mut data = Vec::new();
+    for id in manifest.split(':') {
+        data.extend(std::fs::read(chunk_path(id)).unwrap());
+    }
+    Some(String::from_utf8(data).unwrap())
+}
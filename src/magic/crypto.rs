@@ -0,0 +1,170 @@
+//! At-rest encryption for the CSV files this crate writes to disk.
+//!
+//! Spilled pages, the merge file, `results.csv` and the tombstone set are
+//! written through this layer: instead of writing plaintext we wrap the
+//! underlying `Write`/`Read` in a
+//! streaming ChaCha20-Poly1305 layer: a random 12-byte nonce is written once
+//! up front, followed by a sequence of independently authenticated, length
+//! framed chunks. Truncating or flipping a byte anywhere in a chunk makes
+//! that chunk fail to decrypt instead of silently corrupting the merge.
+//! The oplog (which holds the put/delete mutations), the image chunk store
+//! and the search index do not go through this layer and are plaintext.
+
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use rand::RngCore;
+use std::io::{self, Read, Write};
+
+const NONCE_LEN: usize = 12;
+const LEN_PREFIX: usize = 4;
+
+/// Derive the cipher from the `ENCRYPTION_KEY` env var (read alongside
+/// `BASE_URL`/`NUM_WORKERS`). The var can be any length; we hash it down to
+/// a 32-byte key.
+fn cipher_from_env() -> ChaCha20Poly1305 {
+    let key_str = std::env::var("ENCRYPTION_KEY").expect("ENCRYPTION_KEY must be set");
+    let digest = blake3::hash(key_str.as_bytes());
+    ChaCha20Poly1305::new(Key::from_slice(digest.as_bytes()))
+}
+
+/// Derive a per-chunk nonce from the file's random base nonce and a chunk
+/// counter, so a single 12-byte nonce can safely cover many chunks.
+fn chunk_nonce(base: &[u8; NONCE_LEN], counter: u64) -> Nonce {
+    let mut n = *base;
+    for (b, c) in n[NONCE_LEN - 8..].iter_mut().zip(counter.to_le_bytes()) {
+        *b ^= c;
+    }
+    *Nonce::from_slice(&n)
+}
+
+/// Wraps a `Write` so every call to `write` is sealed as one authenticated,
+/// length-framed ChaCha20-Poly1305 chunk. The first bytes written to the
+/// underlying writer are a fresh random nonce.
+pub(crate) struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    base_nonce: [u8; NONCE_LEN],
+    chunk: u64,
+    wrote_header: bool,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        let mut base_nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut base_nonce);
+        Self {
+            inner,
+            cipher: cipher_from_env(),
+            base_nonce,
+            chunk: 0,
+            wrote_header: false,
+        }
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if !self.wrote_header {
+            self.inner.write_all(&self.base_nonce)?;
+            self.wrote_header = true;
+        }
+        let nonce = chunk_nonce(&self.base_nonce, self.chunk);
+        self.chunk += 1;
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, buf)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failure"))?;
+        self.inner
+            .write_all(&(ciphertext.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&ciphertext)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reverses `EncryptingWriter`: reads the nonce header once, then each
+/// length-prefixed authenticated chunk, handing plaintext back through
+/// `Read`. A chunk that fails authentication (truncated or tampered file)
+/// surfaces as an `io::Error` instead of garbage bytes.
+pub(crate) struct DecryptingReader<R: Read> {
+    inner: R,
+    cipher: ChaCha20Poly1305,
+    base_nonce: Option<[u8; NONCE_LEN]>,
+    chunk: u64,
+    pending: std::collections::VecDeque<u8>,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        Self {
+            inner,
+            cipher: cipher_from_env(),
+            base_nonce: None,
+            chunk: 0,
+            pending: std::collections::VecDeque::new(),
+        }
+    }
+
+    fn read_exact_or_eof(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut read = 0;
+        while read < buf.len() {
+            match self.inner.read(&mut buf[read..])? {
+                0 if read == 0 => return Ok(false),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        Ok(true)
+    }
+
+    fn fill_next_chunk(&mut self) -> io::Result<bool> {
+        if self.base_nonce.is_none() {
+            let mut nonce = [0u8; NONCE_LEN];
+            if !self.read_exact_or_eof(&mut nonce)? {
+                return Ok(false);
+            }
+            self.base_nonce = Some(nonce);
+        }
+
+        let mut len_bytes = [0u8; LEN_PREFIX];
+        if !self.read_exact_or_eof(&mut len_bytes)? {
+            return Ok(false);
+        }
+        let len = u32::from_le_bytes(len_bytes) as usize;
+
+        let mut ciphertext = vec![0u8; len];
+        if !self.read_exact_or_eof(&mut ciphertext)? {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let nonce = chunk_nonce(self.base_nonce.as_ref().unwrap(), self.chunk);
+        self.chunk += 1;
+        let plaintext = self
+            .cipher
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "file tampered or corrupt"))?;
+        self.pending.extend(plaintext);
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pending.is_empty() {
+            if !self.fill_next_chunk()? {
+                return Ok(0);
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        for slot in buf.iter_mut().take(n) {
+            *slot = self.pending.pop_front().unwrap();
+        }
+        Ok(n)
+    }
+}
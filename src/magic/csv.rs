@@ -0,0 +1,105 @@
+//! A minimal RFC 4180 codec.
+//!
+//! The merge pipeline tokenizes each row by uuid/image/version position, and
+//! used to do that with a bare `split(',')`, which silently shifts every
+//! column whenever a `message` or `author` contains a comma, quote, or
+//! newline. `encode_row` quotes fields that need it and `decode_row`/`field`
+//! undo that quoting, so a row is always read back as the fields it was
+//! written with. `read_record` is the matching reader: a row with an
+//! embedded newline spans more than one physical line, so callers must pull
+//! full records through it instead of splitting on `\n` with `BufRead::lines`.
+
+use std::io::BufRead;
+
+/// Join `fields` into a single RFC 4180 row (no trailing newline).
+pub(crate) fn encode_row(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|f| encode_field(f))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn encode_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Split one RFC 4180 row into its fields, unescaping quoted ones.
+pub(crate) fn decode_row(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut chars = line.chars().peekable();
+
+    loop {
+        let mut field = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            loop {
+                match chars.next() {
+                    Some('"') if chars.peek() == Some(&'"') => {
+                        field.push('"');
+                        chars.next();
+                    }
+                    Some('"') | None => break,
+                    Some(c) => field.push(c),
+                }
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c == ',' {
+                    break;
+                }
+                field.push(c);
+                chars.next();
+            }
+        }
+        fields.push(field);
+
+        match chars.next() {
+            Some(',') => continue,
+            _ => break,
+        }
+    }
+
+    fields
+}
+
+/// Pull the field at `index` out of an RFC 4180 row.
+pub(crate) fn field(line: &str, index: usize) -> Option<String> {
+    decode_row(line).into_iter().nth(index)
+}
+
+/// Read one full RFC 4180 record from `reader`, which may span more than one
+/// physical line if a field quotes an embedded newline. Returns `None` at
+/// end of input. A record is complete once its quote count is even — an odd
+/// count means a quoted field is still open, so the next physical line is
+/// part of the same record.
+pub(crate) fn read_record<R: BufRead>(reader: &mut R) -> std::io::Result<Option<String>> {
+    let mut record = String::new();
+    loop {
+        let bytes_read = reader.read_line(&mut record)?;
+        if bytes_read == 0 {
+            return Ok(if record.is_empty() {
+                None
+            } else {
+                Some(trim_newline(record))
+            });
+        }
+        if record.chars().filter(|&c| c == '"').count() % 2 == 0 {
+            return Ok(Some(trim_newline(record)));
+        }
+    }
+}
+
+fn trim_newline(mut record: String) -> String {
+    if record.ends_with('\n') {
+        record.pop();
+        if record.ends_with('\r') {
+            record.pop();
+        }
+    }
+    record
+}
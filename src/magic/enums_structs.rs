@@ -1,7 +1,7 @@
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-#[derive(Serialize, Debug, Deserialize)]
+#[derive(Clone, Serialize, Debug, Deserialize)]
 /// The update that the client sees.
 pub struct ClientPutUpdate {
     pub(crate) author: String,
@@ -10,11 +10,14 @@ pub struct ClientPutUpdate {
     pub(crate) image: Option<String>,
 }
 
-#[derive(Serialize, Debug, Deserialize)]
+#[derive(Clone, Serialize, Debug, Deserialize)]
 pub struct PutDeleteUpdate {
     pub uuid: String,
     pub put: Option<ClientPutUpdate>,
     pub delete: bool,
+    /// Monotonic write time (Lamport-style counter) used to resolve concurrent
+    /// updates to the same uuid as last-write-wins instead of "last file wins".
+    pub timestamp: u64,
 }
 
 #[derive(Serialize, Debug, Deserialize)]
@@ -35,16 +38,18 @@ pub(crate) struct CompleteMessage {
 }
 
 impl CompleteMessage {
-    pub(crate) fn into_csv_row(self) -> String {
-        let row = format!(
-            "{},{},{},{},{}",
-            self.uuid,
-            self.author,
-            self.message,
-            self.likes,
-            self.image.unwrap_or("".to_string())
-        );
-        row
+    /// `version` is the write time this row should be resolved at, carried as
+    /// the trailing CSV column so a later merge can compare it against
+    /// incoming `PutDeleteUpdate::timestamp`s without re-deriving it. It's a
+    /// client-side concept the server has no notion of, so it's passed in by
+    /// the caller rather than decoded off the wire with the rest of the row.
+    pub(crate) fn into_csv_row(self, version: u64) -> String {
+        let likes = self.likes.to_string();
+        // store the image payload as a content-addressed chunk manifest
+        // instead of copying the raw payload into every row that carries it
+        let image = super::chunk_store::encode_image_manifest(self.image.as_deref());
+        let version = version.to_string();
+        super::csv::encode_row(&[&self.uuid, &self.author, &self.message, &likes, &image, &version])
     }
 }
 
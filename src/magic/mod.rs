@@ -1,17 +1,33 @@
+mod chunk_store;
+mod crypto;
+mod csv;
 mod enums_structs;
+mod mutation_queue;
+mod oplog;
+mod page_cache;
+mod search;
 mod state;
 
 use enums_structs::*;
-use state::State;
+use mutation_queue::MutationQueue;
+use page_cache::{PageCache, PAGE_CACHE_BUDGET_BYTES};
+use state::{load_tombstones, State};
 use std::{
-    collections::{BTreeSet, VecDeque},
-    fs::OpenOptions,
-    io::Write,
     path::Path,
     sync::{Arc, Mutex},
 };
 use threadpool::ThreadPool;
 
+pub(crate) fn search(query: String, limit: usize) -> Vec<String> {
+    search::search(&query, limit)
+}
+
+/// Reassemble a row's `image` column (a chunk manifest) back into the
+/// payload it was encoded from.
+pub(crate) fn get_image(manifest: String) -> Option<String> {
+    chunk_store::decode_image_manifest(&manifest)
+}
+
 const RESULT_CSV_NAME: &str = "results.csv";
 const MERGE_FILE_NAME: &str = "merge.csv";
 
@@ -20,24 +36,49 @@ pub(crate) fn get_all_pagination(base_url: String, num_workers: usize) {
     let meta = trigger_pagination(&base_url);
     let total_pages = meta.total_pages;
 
+    // pick up whatever a previous, interrupted run had already made durable
+    // so we don't re-fetch pages that are already safely on disk
+    let recovered = oplog::replay();
+    let recovered_pages = recovered.post_pages.len();
+
+    // every thread shares one mutation stream; the cursor is created now so
+    // it observes every put/delete pushed during this run's fetch phase
+    let mutations = MutationQueue::new();
+    let mutation_cursor = Mutex::new(mutations.cursor());
+    mutations.push(recovered.mutations);
+
     // create a state object that will be shared between threads
     let state = Arc::new(State {
-        cache_number: Mutex::new(0),
-        pages_fetched: Mutex::new(0),
+        pages_fetched: Mutex::new(recovered_pages),
         get_page_url: format!("{base_url}/get-page"),
         meta,
-        cache_number_list: Mutex::new(VecDeque::with_capacity(total_pages)),
-        posts_file_names: Mutex::new(BTreeSet::new()),
+        posts_file_numbers: Mutex::new(recovered.post_pages.into_iter().collect()),
+        tombstones: Mutex::new(load_tombstones()),
+        mutations,
+        mutation_cursor,
+        page_cache: Mutex::new(PageCache::new(PAGE_CACHE_BUDGET_BYTES)),
     });
 
     // a special case flag for the first sync operation
     let first_sync = !Path::new(RESULT_CSV_NAME).exists();
 
+    // every page was already committed by the run we're resuming from, so
+    // there's nothing left to fetch: just run the merge this call would have
+    // triggered on its last page and we're done
+    if total_pages > 0 && recovered_pages == total_pages {
+        if first_sync {
+            state.merge_posts(RESULT_CSV_NAME);
+        } else {
+            state.merge();
+        }
+        return;
+    }
+
     // create a thread pool
     let pool = ThreadPool::new(num_workers);
 
-    // do the work
-    for _ in 0..total_pages {
+    // only fetch the pages that a previous run hasn't already committed
+    for _ in 0..total_pages.saturating_sub(recovered_pages) {
         let s = state.clone();
         pool.execute(move || {
             get_page_and_process(s, first_sync, total_pages);
@@ -67,13 +108,23 @@ fn get_page_and_process(state: Arc<State>, first_sync: bool, total_pages: usize)
         PaginationType::Fresh => {
             let res: DbResults = bincode::deserialize(&body_bytes).unwrap();
 
-            let post_file_name = post_file_name(res.page_number);
             state
-                .posts_file_names
+                .posts_file_numbers
                 .lock()
                 .unwrap()
-                .insert(post_file_name.clone());
-            write_posts_csv(&post_file_name, res.messages);
+                .insert(res.page_number);
+
+            // hold the page resident in memory; only pages the cache actually
+            // spills to disk need a durable record of where they landed
+            let spilled = state
+                .page_cache
+                .lock()
+                .unwrap()
+                .insert(res.page_number, res.messages, post_file_name);
+            for (n, file_name) in spilled {
+                oplog::append_spilled(n, file_name);
+            }
+            oplog::append_commit(res.page_number, Vec::new());
 
             let mut pages_fetched = state.pages_fetched.lock().unwrap();
             *pages_fetched += 1;
@@ -86,13 +137,23 @@ fn get_page_and_process(state: Arc<State>, first_sync: bool, total_pages: usize)
         PaginationType::Cache => {
             let res: MutationResults = bincode::deserialize(&body_bytes).unwrap();
 
-            let post_file_name = post_file_name(res.page_number);
             state
-                .posts_file_names
+                .posts_file_numbers
+                .lock()
+                .unwrap()
+                .insert(res.page_number);
+
+            // hold the page resident in memory; only pages the cache actually
+            // spills to disk need a durable record of where they landed
+            let spilled = state
+                .page_cache
                 .lock()
                 .unwrap()
-                .insert(post_file_name.clone());
-            write_posts_csv(&post_file_name, res.posts);
+                .insert(res.page_number, res.posts, post_file_name);
+            for (n, file_name) in spilled {
+                oplog::append_spilled(n, file_name);
+            }
+            oplog::append_commit(res.page_number, res.puts_deletes.clone());
 
             let mut pages_fetched = state.pages_fetched.lock().unwrap();
             *pages_fetched += 1;
@@ -109,19 +170,9 @@ fn get_page_and_process(state: Arc<State>, first_sync: bool, total_pages: usize)
             drop(pages_fetched);
 
             if !res.puts_deletes.is_empty() {
-                let cache_num;
-                {
-                    let mut cache_number = state.cache_number.lock().unwrap();
-                    *cache_number += 1;
-                    cache_num = *cache_number;
-                }
-                state.cache_number_list.lock().unwrap().push_back(cache_num);
-
-                // create a new file called `cached_mutations_{}.csv`
-                let file_name = put_delete_file_name(cache_num);
-                // dump puts deletes to the file
-                let encoded = bincode::serialize(&res.puts_deletes).unwrap();
-                std::fs::write(file_name, encoded).unwrap();
+                // broadcast this page's mutations onto the shared stream instead of
+                // dumping them to their own `cached_mutations_*` file
+                state.mutations.push(res.puts_deletes);
             }
 
             if *state.pages_fetched.lock().unwrap() == state.meta.total_pages {
@@ -131,28 +182,6 @@ fn get_page_and_process(state: Arc<State>, first_sync: bool, total_pages: usize)
     };
 }
 
-fn post_file_name(n: usize) -> String {
+pub(crate) fn post_file_name(n: usize) -> String {
     format!("posts_{n}.csv")
 }
-
-fn put_delete_file_name(num: usize) -> String {
-    let file_name = format!("cached_mutations_{}", num);
-    file_name
-}
-
-fn write_posts_csv(file_name: &str, posts: Vec<CompleteMessage>) {
-    let mut file = OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(file_name)
-        .unwrap();
-    // append to the file
-    for post in posts {
-        // each csv row is this format: uuid,message,author,likes,image
-        let row = post.into_csv_row();
-        // write the row to the file
-        writeln!(file, "{}", row).unwrap();
-    }
-    // flush the file
-    file.flush().unwrap();
-}
@@ -0,0 +1,123 @@
+//! A broadcast queue of put/delete mutations, modeled on tantivy's
+//! `DeleteQueue`, that replaces the old one-file-per-cache-round dump.
+//!
+//! Producers push a `Vec<PutDeleteUpdate>` as a new immutable block; any
+//! number of independent `DeleteCursor`s can then replay the sequence of
+//! blocks enqueued *after* they were created, each advancing at its own
+//! pace. That means several merge passes (e.g. a re-merge after a failed
+//! run) can all walk the identical mutation stream without re-reading files
+//! or stepping on each other.
+
+use std::sync::{Arc, RwLock};
+
+use super::PutDeleteUpdate;
+
+enum NextBlock {
+    /// Nothing has been appended after this block yet.
+    Pending,
+    /// The block that was appended right after this one.
+    Next(Arc<Block>),
+}
+
+struct Block {
+    operations: Arc<[PutDeleteUpdate]>,
+    next: RwLock<NextBlock>,
+}
+
+impl Block {
+    fn empty() -> Arc<Block> {
+        Arc::new(Block {
+            operations: Arc::from(Vec::new()),
+            next: RwLock::new(NextBlock::Pending),
+        })
+    }
+}
+
+struct Inner {
+    last_block: Arc<Block>,
+}
+
+/// Handle shared by every producer and every cursor onto one mutation stream.
+#[derive(Clone)]
+pub(crate) struct MutationQueue {
+    inner: Arc<RwLock<Inner>>,
+}
+
+impl MutationQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(Inner {
+                last_block: Block::empty(),
+            })),
+        }
+    }
+
+    /// Append a new block of operations. Every cursor sitting at the current
+    /// tail will see it on its next call to `next()`.
+    pub(crate) fn push(&self, operations: Vec<PutDeleteUpdate>) {
+        if operations.is_empty() {
+            return;
+        }
+        let mut inner = self.inner.write().unwrap();
+        let new_block = Arc::new(Block {
+            operations: operations.into(),
+            next: RwLock::new(NextBlock::Pending),
+        });
+        *inner.last_block.next.write().unwrap() = NextBlock::Next(new_block.clone());
+        inner.last_block = new_block;
+    }
+
+    /// A cursor that only observes operations pushed after this call.
+    pub(crate) fn cursor(&self) -> DeleteCursor {
+        DeleteCursor {
+            block: self.inner.read().unwrap().last_block.clone(),
+            index: 0,
+        }
+    }
+}
+
+/// Walks the mutation stream one operation at a time. Cloning a cursor forks
+/// an independent copy at the same position, so a second pass can replay the
+/// exact same remaining mutations without disturbing the original.
+#[derive(Clone)]
+pub(crate) struct DeleteCursor {
+    block: Arc<Block>,
+    index: usize,
+}
+
+impl DeleteCursor {
+    /// Pop the next operation in the stream, or `None` if nothing has been
+    /// pushed past this cursor's position yet.
+    pub(crate) fn next(&mut self) -> Option<PutDeleteUpdate> {
+        loop {
+            if self.index < self.block.operations.len() {
+                let op = self.block.operations[self.index].clone();
+                self.index += 1;
+                return Some(op);
+            }
+            // this block is drained; see if another one has been appended
+            let next = match &*self.block.next.read().unwrap() {
+                NextBlock::Next(b) => Some(b.clone()),
+                NextBlock::Pending => None,
+            };
+            match next {
+                Some(b) => {
+                    self.block = b;
+                    self.index = 0;
+                }
+                None => return None,
+            }
+        }
+    }
+
+    /// Drain every operation pushed past this cursor's position into a Vec,
+    /// consuming the cursor. Used to collapse same-uuid updates up front
+    /// instead of walking the stream one operation at a time.
+    pub(crate) fn drain(mut self) -> Vec<PutDeleteUpdate> {
+        let mut operations = Vec::new();
+        while let Some(op) = self.next() {
+            operations.push(op);
+        }
+        operations
+    }
+}
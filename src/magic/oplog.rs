@@ -0,0 +1,164 @@
+//! A durable, append-only operation log so a crash mid-sync can resume
+//! instead of starting the whole pagination over.
+//!
+//! Every processed page appends a *commit* record capturing its put/delete
+//! mutations right away, since those never depend on anything touching
+//! disk. A page's rows, on the other hand, may sit resident in the page
+//! cache for a while before they're spilled to disk (see `page_cache.rs`);
+//! whenever that happens (immediately, or later when an older page is
+//! evicted to make room) a *spilled* record is appended with the file's
+//! length at that time. On startup `replay()` walks the log and treats a
+//! page as durably fetched only if it has both a commit and a spilled
+//! record whose file still has the recorded length — a page that was still
+//! resident when the process died is simply re-fetched, same as a page
+//! that was never started, while its mutations (already committed) are
+//! still replayed into a fresh `MutationQueue`. On a later sync the log is
+//! only deleted once the final merge's rename succeeds, so a run that dies
+//! before that point always has something to resume from. The first sync's
+//! merge writes `results.csv` in place, with no rename, and deletes the log
+//! once that file is written; a run that dies while it is being written
+//! leaves a partial `results.csv` behind.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+
+use super::PutDeleteUpdate;
+
+const LOG_FILE_NAME: &str = "oplog";
+
+#[derive(serde::Serialize, serde::Deserialize)]
+enum LogRecord {
+    /// `page_number` was fully processed this run; its mutations are
+    /// durable from this point on regardless of whether its rows have been
+    /// spilled to disk yet.
+    Commit {
+        page_number: usize,
+        mutations: Vec<PutDeleteUpdate>,
+    },
+    /// `post_file` for `page_number` was fully written and is `byte_len`
+    /// bytes long; trusted on replay only if the file on disk still has
+    /// that exact length.
+    Spilled {
+        page_number: usize,
+        post_file: String,
+        byte_len: u64,
+    },
+}
+
+fn append(record: &LogRecord) {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(LOG_FILE_NAME)
+        .unwrap();
+    let encoded = bincode::serialize(record).unwrap();
+    file.write_all(&(encoded.len() as u32).to_le_bytes())
+        .unwrap();
+    file.write_all(&encoded).unwrap();
+    // `flush` is a no-op on a raw `File` — the record isn't actually durable
+    // until it's synced to disk, which is the entire point of this log
+    file.sync_all().unwrap();
+}
+
+/// Record that a page was fully processed, capturing its mutations durably
+/// regardless of whether its rows have reached disk yet.
+pub(crate) fn append_commit(page_number: usize, mutations: Vec<PutDeleteUpdate>) {
+    append(&LogRecord::Commit {
+        page_number,
+        mutations,
+    });
+}
+
+/// Record that `post_file` for `page_number` was fully written to disk.
+pub(crate) fn append_spilled(page_number: usize, post_file: String) {
+    let byte_len = std::fs::metadata(&post_file).map(|m| m.len()).unwrap_or(0);
+    append(&LogRecord::Spilled {
+        page_number,
+        post_file,
+        byte_len,
+    });
+}
+
+/// Everything a previous, interrupted run had already made durable.
+pub(crate) struct Recovered {
+    /// Page numbers whose rows are confirmed on disk and safe to skip
+    /// re-fetching.
+    pub(crate) post_pages: HashSet<usize>,
+    pub(crate) mutations: Vec<PutDeleteUpdate>,
+}
+
+/// Replay the log, if any. A page only counts as durably fetched if it has
+/// both a commit record and a spilled record whose file still has the
+/// length that was recorded when it was written; otherwise its rows never
+/// reliably reached disk and it needs to be re-fetched, even though its
+/// mutations (captured at commit time, independent of the file) are always
+/// replayed.
+pub(crate) fn replay() -> Recovered {
+    let mut committed: HashSet<usize> = HashSet::new();
+    let mut spilled: HashMap<usize, (String, u64)> = HashMap::new();
+    let mut mutations = Vec::new();
+
+    let Ok(file) = File::open(LOG_FILE_NAME) else {
+        return Recovered {
+            post_pages: HashSet::new(),
+            mutations,
+        };
+    };
+    let mut reader = BufReader::new(file);
+
+    loop {
+        let mut len_bytes = [0u8; 4];
+        if reader.read_exact(&mut len_bytes).is_err() {
+            break;
+        }
+        let len = u32::from_le_bytes(len_bytes) as usize;
+        let mut buf = vec![0u8; len];
+        if reader.read_exact(&mut buf).is_err() {
+            // truncated trailing record from a crash mid-write; stop here
+            break;
+        }
+        let Ok(record) = bincode::deserialize::<LogRecord>(&buf) else {
+            break;
+        };
+        match record {
+            LogRecord::Commit {
+                page_number,
+                mutations: m,
+            } => {
+                committed.insert(page_number);
+                mutations.extend(m);
+            }
+            LogRecord::Spilled {
+                page_number,
+                post_file,
+                byte_len,
+            } => {
+                spilled.insert(page_number, (post_file, byte_len));
+            }
+        }
+    }
+
+    let post_pages = committed
+        .into_iter()
+        .filter(|n| {
+            spilled.get(n).is_some_and(|(post_file, byte_len)| {
+                std::fs::metadata(post_file)
+                    .map(|m| m.len() == *byte_len)
+                    .unwrap_or(false)
+            })
+        })
+        .collect();
+
+    Recovered {
+        post_pages,
+        mutations,
+    }
+}
+
+/// Drop the log once the final merge has written `results.csv` (renamed into
+/// place on a later sync, written in place on the first), so the next run
+/// starts clean instead of replaying state that's already in `results.csv`.
+pub(crate) fn clear() {
+    let _ = std::fs::remove_file(LOG_FILE_NAME);
+}
@@ -0,0 +1,144 @@
+//! Holds freshly-fetched pages resident in memory so the common small-sync
+//! case never round-trips through the filesystem; once resident data
+//! exceeds `budget_bytes` the least-recently-inserted page is spilled to
+//! disk (encrypted at rest, like `results.csv`) and dropped from
+//! RAM. A page that's still resident when the process crashes is simply
+//! re-fetched on the next run, the same as a page that was never started.
+
+use super::{
+    crypto::{DecryptingReader, EncryptingWriter},
+    csv,
+    enums_structs::CompleteMessage,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Write};
+
+/// How much page data the cache holds resident in memory before the
+/// least-recently-inserted page is spilled to disk.
+pub(crate) const PAGE_CACHE_BUDGET_BYTES: usize = 50_000_000;
+
+/// The version a freshly-fetched row (one the server has no `version` notion
+/// of) is resolved at, same as a brand new row starting from scratch.
+const FRESH_ROW_VERSION: u64 = 0;
+
+/// A page's fetched rows, either still resident in memory or already
+/// spilled to disk and dropped from RAM.
+enum PageEntry {
+    Resident(Vec<CompleteMessage>),
+    Spilled,
+}
+
+pub(crate) struct PageCache {
+    entries: HashMap<usize, PageEntry>,
+    sizes: HashMap<usize, usize>,
+    lru: VecDeque<usize>,
+    resident_bytes: usize,
+    budget_bytes: usize,
+}
+
+impl PageCache {
+    pub(crate) fn new(budget_bytes: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            sizes: HashMap::new(),
+            lru: VecDeque::new(),
+            resident_bytes: 0,
+            budget_bytes,
+        }
+    }
+
+    /// Hold `messages` resident for `page_number`, spilling
+    /// least-recently-inserted resident pages (to the file `file_name`
+    /// computes for their page number) until the cache is back under
+    /// budget. Returns the page numbers and file names that were spilled,
+    /// so the caller can make their durability durable in the oplog.
+    pub(crate) fn insert(
+        &mut self,
+        page_number: usize,
+        messages: Vec<CompleteMessage>,
+        file_name: impl Fn(usize) -> String,
+    ) -> Vec<(usize, String)> {
+        let size = estimate_size(&messages);
+        self.entries
+            .insert(page_number, PageEntry::Resident(messages));
+        self.sizes.insert(page_number, size);
+        self.lru.push_back(page_number);
+        self.resident_bytes += size;
+
+        let mut spilled = Vec::new();
+        while self.resident_bytes > self.budget_bytes {
+            let Some(n) = self.lru.pop_front() else {
+                break;
+            };
+            let Some(PageEntry::Resident(messages)) = self.entries.remove(&n) else {
+                // already spilled or taken by a merge; nothing left to demote
+                continue;
+            };
+            let name = file_name(n);
+            write_spilled(&name, messages);
+            self.entries.insert(n, PageEntry::Spilled);
+            self.resident_bytes -= self.sizes.remove(&n).unwrap_or(0);
+            spilled.push((n, name));
+        }
+        spilled
+    }
+
+    /// Take ownership of `page_number`'s rows as CSV lines. Reads from and
+    /// decrypts `file_name` if the page was spilled there, or was never
+    /// inserted into this cache at all (a page recovered from a previous
+    /// run, which only ever reaches this cache's caller once it's durably
+    /// on disk).
+    pub(crate) fn take_lines(&mut self, page_number: usize, file_name: &str) -> Vec<String> {
+        match self.entries.remove(&page_number) {
+            Some(PageEntry::Resident(messages)) => {
+                self.resident_bytes -= self.sizes.remove(&page_number).unwrap_or(0);
+                messages
+                    .into_iter()
+                    .map(|m| m.into_csv_row(FRESH_ROW_VERSION))
+                    .collect()
+            }
+            Some(PageEntry::Spilled) | None => read_spilled(file_name),
+        }
+    }
+}
+
+fn write_spilled(file_name: &str, messages: Vec<CompleteMessage>) {
+    // truncate rather than append: a page number is only ever spilled once,
+    // and truncating self-heals against a stale partial file left behind by
+    // a crash during a previous attempt at writing this same page
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(file_name)
+        .unwrap();
+    let mut writer = EncryptingWriter::new(file);
+    for message in messages {
+        writeln!(writer, "{}", message.into_csv_row(FRESH_ROW_VERSION)).unwrap();
+    }
+    writer.flush().unwrap();
+}
+
+fn read_spilled(file_name: &str) -> Vec<String> {
+    let mut reader = BufReader::new(DecryptingReader::new(File::open(file_name).unwrap()));
+    let mut lines = Vec::new();
+    while let Some(line) = csv::read_record(&mut reader).unwrap() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Rough in-memory footprint of a page's rows, for the cache's eviction budget.
+fn estimate_size(messages: &[CompleteMessage]) -> usize {
+    messages
+        .iter()
+        .map(|m| {
+            std::mem::size_of::<CompleteMessage>()
+                + m.uuid.len()
+                + m.author.len()
+                + m.message.len()
+                + m.image.as_deref().map_or(0, str::len)
+        })
+        .sum()
+}
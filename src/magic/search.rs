@@ -0,0 +1,133 @@
+//! Full-text search over synced messages, so callers can look a message up
+//! without a server round-trip or a linear scan over `results.csv`.
+//!
+//! The index tracks the same rows `State::merge`/`State::merge_posts` write:
+//! every put (re-)indexes its row, keyed by `uuid`, and every delete removes
+//! it, so the index stays in lockstep with the final CSV.
+
+use tantivy::collector::TopDocs;
+use tantivy::query::QueryParser;
+use tantivy::schema::{Field, Schema, FAST, STORED, STRING, TEXT};
+use tantivy::{doc, Index, IndexWriter, Term};
+
+use super::csv;
+
+const INDEX_DIR: &str = "search_index";
+/// Writer heap size; tantivy requires at least 15MB per indexing thread.
+const WRITER_HEAP_BYTES: usize = 15_000_000;
+
+fn schema() -> Schema {
+    let mut builder = Schema::builder();
+    builder.add_text_field("uuid", STRING | STORED);
+    builder.add_text_field("author", TEXT | STORED);
+    builder.add_text_field("message", TEXT | STORED);
+    builder.add_i64_field("likes", FAST | STORED);
+    builder.build()
+}
+
+fn open_or_create_index() -> Index {
+    std::fs::create_dir_all(INDEX_DIR).unwrap();
+    let dir = tantivy::directory::MmapDirectory::open(INDEX_DIR).unwrap();
+    Index::open_or_create(dir, schema()).unwrap()
+}
+
+struct Fields {
+    uuid: Field,
+    author: Field,
+    message: Field,
+    likes: Field,
+}
+
+impl Fields {
+    fn from_schema(schema: &Schema) -> Self {
+        Self {
+            uuid: schema.get_field("uuid").unwrap(),
+            author: schema.get_field("author").unwrap(),
+            message: schema.get_field("message").unwrap(),
+            likes: schema.get_field("likes").unwrap(),
+        }
+    }
+}
+
+/// Incrementally applies the puts/deletes a merge pass produces, committing
+/// once when the merge is done.
+pub(crate) struct SearchIndexer {
+    index: Index,
+    writer: IndexWriter,
+    fields: Fields,
+}
+
+impl SearchIndexer {
+    pub(crate) fn open() -> Self {
+        let index = open_or_create_index();
+        let writer = index.writer(WRITER_HEAP_BYTES).unwrap();
+        let fields = Fields::from_schema(&index.schema());
+        Self {
+            index,
+            writer,
+            fields,
+        }
+    }
+
+    /// (Re-)index a final CSV row of the form `uuid,author,message,likes,image,version`.
+    pub(crate) fn put(&mut self, row: &str) {
+        let fields = csv::decode_row(row);
+        let (Some(uuid), Some(author), Some(message), Some(likes)) = (
+            fields.first(),
+            fields.get(1),
+            fields.get(2),
+            fields.get(3).and_then(|l| l.parse::<i64>().ok()),
+        ) else {
+            return;
+        };
+
+        // a uuid is unique, so clear out any previous version of this row first
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.uuid, uuid));
+        self.writer
+            .add_document(doc!(
+                self.fields.uuid => uuid.as_str(),
+                self.fields.author => author.as_str(),
+                self.fields.message => message.as_str(),
+                self.fields.likes => likes,
+            ))
+            .unwrap();
+    }
+
+    /// Drop a tombstoned uuid from the index.
+    pub(crate) fn delete(&mut self, uuid: &str) {
+        self.writer
+            .delete_term(Term::from_field_text(self.fields.uuid, uuid));
+    }
+
+    pub(crate) fn commit(mut self) {
+        self.writer.commit().unwrap();
+        // keep the index object alive until after commit so the writer's
+        // lock on it is released in order
+        drop(self.index);
+    }
+}
+
+/// Search the index over author+message text, returning matching uuids
+/// ranked by score, best match first.
+pub(crate) fn search(query: &str, limit: usize) -> Vec<String> {
+    let index = open_or_create_index();
+    let fields = Fields::from_schema(&index.schema());
+    let reader = index.reader().unwrap();
+    let searcher = reader.searcher();
+
+    let parser = QueryParser::for_index(&index, vec![fields.author, fields.message]);
+    let Ok(parsed) = parser.parse_query(query) else {
+        return Vec::new();
+    };
+
+    searcher
+        .search(&parsed, &TopDocs::with_limit(limit))
+        .unwrap()
+        .into_iter()
+        .filter_map(|(_score, addr)| {
+            let doc = searcher.doc(addr).ok()?;
+            doc.get_first(fields.uuid)?.as_text().map(str::to_string)
+        })
+        .collect()
+}
@@ -1,313 +1,329 @@
 use super::{
-    enums_structs::PaginationMetadata, put_delete_file_name, PutDeleteUpdate, MERGE_FILE_NAME,
-    RESULT_CSV_NAME,
+    crypto::{DecryptingReader, EncryptingWriter},
+    csv,
+    enums_structs::{PaginationMetadata, PutDeleteUpdate},
+    mutation_queue::{DeleteCursor, MutationQueue},
+    oplog,
+    page_cache::PageCache,
+    post_file_name,
+    search::SearchIndexer,
+    MERGE_FILE_NAME, RESULT_CSV_NAME,
 };
-use std::io::BufRead;
 use std::sync::Mutex;
 use std::{
     self,
-    collections::{BTreeSet, VecDeque},
+    cmp::Reverse,
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, BinaryHeap, HashMap},
     fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Write},
 };
 
-pub struct ReadResultLine {
-    /// the read result line
+/// Where the observed-remove tombstone set is persisted between runs, so that
+/// a delete from an earlier sync can still outrank a stale put encountered later.
+const TOMBSTONE_FILE_NAME: &str = "tombstones";
+
+/// Load the tombstone set (uuid -> delete timestamp) from disk, if it exists.
+pub(crate) fn load_tombstones() -> HashMap<String, u64> {
+    match File::open(TOMBSTONE_FILE_NAME) {
+        Ok(file) => bincode::deserialize_from(DecryptingReader::new(file)).unwrap_or_default(),
+        Err(_) => HashMap::new(),
+    }
+}
+
+/// Persist the tombstone set so a future run can still see past deletes.
+fn save_tombstones(tombstones: &HashMap<String, u64>) {
+    let encoded = bincode::serialize(tombstones).unwrap();
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(TOMBSTONE_FILE_NAME)
+        .unwrap();
+    let mut writer = EncryptingWriter::new(file);
+    writer.write_all(&encoded).unwrap();
+    writer.flush().unwrap();
+}
+
+/// Pull the `version` column (the last field) out of a result line.
+fn line_version(line: &str) -> u64 {
+    csv::field(line, 5).unwrap().parse().unwrap_or(0)
+}
+
+/// Pull the uuid (the first field) out of a result line.
+fn line_uuid(line: &str) -> String {
+    csv::field(line, 0).unwrap()
+}
+
+/// One source's current head line in the k-way merge, ordered by uuid so the
+/// merge can always pop the globally smallest line next.
+struct HeapEntry {
+    uuid: String,
     line: String,
-    /// A flag to indicate that this result line was updated by a put or delete update.
-    /// This is used to skip updating the result line again
-    updated: bool,
-    mark_for_deletion: bool,
+    source: MergeSource,
 }
 
-impl ReadResultLine {
-    fn new(line: String) -> Self {
-        Self {
-            line,
-            updated: false,
-            mark_for_deletion: false,
-        }
+impl HeapEntry {
+    fn new(line: String, source: MergeSource) -> Self {
+        let uuid = line_uuid(&line);
+        Self { uuid, line, source }
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.uuid == other.uuid
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
     }
 }
 
+impl Ord for HeapEntry {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.uuid.cmp(&other.uuid)
+    }
+}
+
+/// Which line iterator a `HeapEntry` was popped from.
+enum MergeSource {
+    /// The previous sync's `results.csv`; the only source put/delete updates apply to.
+    Results,
+    /// `post_iters[_]`, one of the pages being merged in.
+    Post(usize),
+}
+
 pub(crate) struct State {
-    pub(crate) cache_number: Mutex<usize>,
     pub(crate) pages_fetched: Mutex<usize>,
     pub(crate) get_page_url: String,
     pub(crate) meta: PaginationMetadata,
-    pub(crate) cache_number_list: Mutex<VecDeque<usize>>,
-    pub(crate) posts_file_names: Mutex<BTreeSet<String>>,
+    pub(crate) posts_file_numbers: Mutex<BTreeSet<usize>>,
+    /// Observed-remove tombstones: uuid -> timestamp of the delete that last won.
+    pub(crate) tombstones: Mutex<HashMap<String, u64>>,
+    /// The shared broadcast stream of put/delete mutations.
+    pub(crate) mutations: MutationQueue,
+    /// A cursor onto `mutations`, created before this run's fetch phase started
+    /// so it observes every mutation pushed during this sync.
+    pub(crate) mutation_cursor: Mutex<DeleteCursor>,
+    /// Pages fetched this run, held resident until evicted or merged; spilled
+    /// pages are read back from disk through this same cache.
+    pub(crate) page_cache: Mutex<PageCache>,
 }
 
 impl State {
-    /// merge all files, saved by each thread, that contain complete messages into a single file called `to`
+    /// Merge every page a sync fetched into a single file called `to`, via a
+    /// k-way merge keyed by uuid so the output stays sorted regardless of how
+    /// the server partitioned uuids across pages.
     pub(crate) fn merge_posts(&self, to: &str) {
-        let mut writer = BufWriter::new(
+        let mut writer = BufWriter::new(EncryptingWriter::new(
             OpenOptions::new()
                 .write(true)
                 .create(true)
                 .open(to)
                 .unwrap(),
-        );
-        let mut file_names = self.posts_file_names.lock().unwrap();
-        while let Some(file_name) = file_names.pop_first() {
-            let mut post = BufReader::new(File::open(file_name).unwrap()).lines();
-            while let Some(line) = post.next().map(|l| l.unwrap()) {
-                writeln!(writer, "{}", line).unwrap();
+        ));
+        let mut indexer = SearchIndexer::open();
+
+        let mut page_numbers = self.posts_file_numbers.lock().unwrap();
+        let mut cache = self.page_cache.lock().unwrap();
+        let mut post_iters: Vec<std::vec::IntoIter<String>> = Vec::new();
+        while let Some(page_number) = page_numbers.pop_first() {
+            post_iters.push(cache.take_lines(page_number, &post_file_name(page_number)).into_iter());
+        }
+
+        let mut heap: BinaryHeap<Reverse<HeapEntry>> = BinaryHeap::new();
+        for (i, iter) in post_iters.iter_mut().enumerate() {
+            if let Some(line) = iter.next() {
+                heap.push(Reverse(HeapEntry::new(line, MergeSource::Post(i))));
+            }
+        }
+
+        while let Some(Reverse(entry)) = heap.pop() {
+            let MergeSource::Post(i) = entry.source else {
+                unreachable!("merge_posts only ever pushes Post entries");
+            };
+            if let Some(line) = post_iters[i].next() {
+                heap.push(Reverse(HeapEntry::new(line, MergeSource::Post(i))));
             }
+            indexer.put(&entry.line);
+            writeln!(writer, "{}", entry.line).unwrap();
         }
+        writer.flush().unwrap();
+
+        indexer.commit();
+        // this run's pages are now all in `to`; a crash from here on would
+        // have nothing left to resume
+        oplog::clear();
     }
 
-    /// Merge between a group of _posts_ cached files and the previous sync results while applying
-    /// _put_ and _delete_ updates to the results.
+    /// Merge every page a sync fetched with the previous sync's results while
+    /// applying put/delete updates, via a k-way merge keyed by uuid: the
+    /// previous results file and every page each get a line iterator, and
+    /// the smallest head line across all of them is popped and written in
+    /// turn. This keeps `results.csv` globally sorted even if the server's
+    /// uuid ranges overlap across page boundaries.
     pub(crate) fn merge(&self) {
         println!("Merging results...");
 
+        let mut indexer = SearchIndexer::open();
+
         // create a new filed called `final.csv` even if it exists
-        let mut final_writer = BufWriter::new(
+        let mut final_writer = BufWriter::new(EncryptingWriter::new(
             OpenOptions::new()
                 .create(true)
                 .write(true)
                 .open(MERGE_FILE_NAME)
                 .unwrap(),
-        );
+        ));
         // open the `results.csv` file
-        let mut results_reader =
-            BufReader::new(OpenOptions::new().read(true).open(RESULT_CSV_NAME).unwrap()).lines();
-
-        // a queue of put and delete updates
-        // these updates are sorted by uuid already
-        let mut puts_deletes = VecDeque::new();
-
-        // a flag to indicate if we should look for a put or delete update for the current result line
-        let mut should_update_results = !self.cache_number_list.lock().unwrap().is_empty();
-
-        // check if we we have any cached post updates that we need to merge with the old result lines
-        // if not we can just skip the main merge loop entirely
-        let mut cached_post_file_names = self.posts_file_names.lock().unwrap();
-        let Some(cached_file_name) = cached_post_file_names.pop_first() else {
-            // we don't have any cached post updates so we can just write the remaining old result lines
-            // while applying any put or delete updates
-            for mut result_line in results_reader.map(Result::unwrap).map(ReadResultLine::new) {
-                // apply a put or delete update if there is one for this result line
-                self.update_post_line_with_put_delete(
-                    &mut should_update_results,
-                    &mut puts_deletes,
-                    &mut result_line,
-                );
-
-                // if the result line is not marked for deletion, write it to the final results file
-                if !result_line.mark_for_deletion {
-                    writeln!(final_writer, "{}", result_line.line).unwrap();
-                }
-            }
+        let mut results_reader = BufReader::new(DecryptingReader::new(
+            OpenOptions::new().read(true).open(RESULT_CSV_NAME).unwrap(),
+        ));
 
-            // rename the merge file to the final results file
-            std::fs::rename(MERGE_FILE_NAME, RESULT_CSV_NAME).unwrap();
-
-            return;
-        };
+        // drain every put/delete observed since this run's fetch phase started
+        // and collapse same-uuid updates to one resolved effect each, so a
+        // second update to a uuid within this merge can't get stuck behind a
+        // mismatched uuid at the front of the stream (the old pop-one/push-
+        // front scheme only ever looked at the head operation, so a second
+        // update to an already-matched uuid earlier in the file blocked every
+        // operation behind it for the rest of the merge)
+        let cursor = self.mutation_cursor.lock().unwrap().clone();
+        let mut puts_deletes = collapse_puts_deletes(cursor.drain());
 
-        // we have at least one cached post file to merge with the old results
-        let mut cached_posts_reader = BufReader::new(
-            OpenOptions::new()
-                .read(true)
-                .open(cached_file_name)
-                .unwrap(),
-        )
-        .lines();
-
-        // prepare 2 buffers for already read lines that they are not selected to
-        // be written to the final results file in the iteration of loop
-        let mut read_cached_post: Option<String> = None;
-        let mut read_result: Option<ReadResultLine> = None;
-
-        loop {
-            // get a line from the buffer first
-            let mut read_result_line = match read_result.take() {
-                Some(l) => l,
-                // if the buffer is empty, read a line from the previous sync results file
-                None => match results_reader.next() {
-                    // Some(l) => l.unwrap(),
-                    Some(l) => ReadResultLine::new(l.unwrap()),
-                    None => {
-                        // we have reached the end of the previous sync results file
-                        // go write the remaining cached post lines outside the loop
-                        break;
-                    }
-                },
-            };
+        let mut post_numbers = self.posts_file_numbers.lock().unwrap();
+        let mut cache = self.page_cache.lock().unwrap();
+        let mut post_iters: Vec<std::vec::IntoIter<String>> = Vec::new();
+        while let Some(page_number) = post_numbers.pop_first() {
+            post_iters.push(cache.take_lines(page_number, &post_file_name(page_number)).into_iter());
+        }
 
-            // If this result line has not been marked as updated, try update it if uuid matches.
-            if !read_result_line.updated {
-                // apply a put or delete update to the current result line if needed
-                self.update_post_line_with_put_delete(
-                    &mut should_update_results,
-                    &mut puts_deletes,
-                    &mut read_result_line,
-                );
+        let mut heap: BinaryHeap<Reverse<HeapEntry>> = BinaryHeap::new();
+        if let Some(line) = csv::read_record(&mut results_reader).unwrap() {
+            heap.push(Reverse(HeapEntry::new(line, MergeSource::Results)));
+        }
+        for (i, iter) in post_iters.iter_mut().enumerate() {
+            if let Some(line) = iter.next() {
+                heap.push(Reverse(HeapEntry::new(line, MergeSource::Post(i))));
             }
+        }
 
-            // read a line from the current cached post file
-            // if there is a break here, don't forget to write the current result line
-            // to the final results file or else it will be lost
-            let cached_post_line = match read_cached_post.take() {
-                Some(l) => l,
-                None => match cached_posts_reader.next() {
-                    Some(l) => l.unwrap(),
-                    None => {
-                        // we have reached the end of this current cached post file
-                        // load the next post cached file if there is more
-                        match cached_post_file_names.pop_first() {
-                            Some(file_name) => {
-                                // we still have more post cached file to load
-                                cached_posts_reader = BufReader::new(
-                                    OpenOptions::new().read(true).open(file_name).unwrap(),
-                                )
-                                .lines();
-                                // read the first line of the new cached post file
-                                match cached_posts_reader.next() {
-                                    Some(l) => l.unwrap(),
-                                    None => {
-                                        // somehow this file doesn't have any lines???
-                                        if !read_result_line.mark_for_deletion {
-                                            writeln!(final_writer, "{}", read_result_line.line)
-                                                .unwrap();
-                                        }
-                                        break;
-                                    }
-                                }
-                            }
-                            None => {
-                                // There is no more post cached file to read, so we should write the result line (if not marked for deletion)
-                                // and break out of the loop to write the remaining `result_line` lines
-                                if !read_result_line.mark_for_deletion {
-                                    writeln!(final_writer, "{}", read_result_line.line).unwrap();
-                                }
-                                break;
-                            }
-                        }
+        while let Some(Reverse(entry)) = heap.pop() {
+            match entry.source {
+                MergeSource::Post(i) => {
+                    if let Some(line) = post_iters[i].next() {
+                        heap.push(Reverse(HeapEntry::new(line, MergeSource::Post(i))));
                     }
-                },
-            };
-
-            // check to see what should be written to the final file in this iteration
-            if read_result_line.line.split(',').next().unwrap()
-                < cached_post_line.split(',').next().unwrap()
-            {
-                // we should write the result line
-                if !read_result_line.mark_for_deletion {
-                    writeln!(final_writer, "{}", read_result_line.line).unwrap();
+                    indexer.put(&entry.line);
+                    writeln!(final_writer, "{}", entry.line).unwrap();
                 }
-                // save the cached post line for the next iteration
-                read_cached_post = Some(cached_post_line);
-            } else {
-                // we should write the cached post line
-                writeln!(final_writer, "{}", cached_post_line).unwrap();
-                // save the result line for the next iteration if it is not marked for deletion by the put update
-                if !read_result_line.mark_for_deletion {
-                    read_result = Some(read_result_line);
+                MergeSource::Results => {
+                    if let Some(line) = csv::read_record(&mut results_reader).unwrap() {
+                        heap.push(Reverse(HeapEntry::new(line, MergeSource::Results)));
+                    }
+                    match self.resolve_result_line(&mut puts_deletes, &entry.uuid, entry.line) {
+                        Some(line) => {
+                            indexer.put(&line);
+                            writeln!(final_writer, "{}", line).unwrap();
+                        }
+                        None => indexer.delete(&entry.uuid),
+                    }
                 }
             }
-        } // end of loop
-
-        // write the remaining cached post lines if there are any
-        if let Some(l) = read_cached_post.take() {
-            writeln!(final_writer, "{}", l).unwrap();
-        }
-        for line in cached_posts_reader {
-            writeln!(final_writer, "{}", line.unwrap()).unwrap();
-        }
-
-        // write the remaining old result lines if there are any
-        if let Some(mut result_line) = read_result.take() {
-            self.update_post_line_with_put_delete(
-                &mut should_update_results,
-                &mut puts_deletes,
-                &mut result_line,
-            );
-            if !result_line.mark_for_deletion {
-                writeln!(final_writer, "{}", result_line.line).unwrap();
-            }
-        }
-        for mut result_line in results_reader.map(Result::unwrap).map(ReadResultLine::new) {
-            self.update_post_line_with_put_delete(
-                &mut should_update_results,
-                &mut puts_deletes,
-                &mut result_line,
-            );
-            if !result_line.mark_for_deletion {
-                writeln!(final_writer, "{}", result_line.line).unwrap();
-            }
         }
 
+        final_writer.flush().unwrap();
         // rename the merge file to the final results file
         std::fs::rename(MERGE_FILE_NAME, RESULT_CSV_NAME).unwrap();
+        save_tombstones(&self.tombstones.lock().unwrap());
+        indexer.commit();
+        oplog::clear();
     }
 
-    /// Update if there is a put update for it.
-    pub(crate) fn update_post_line_with_put_delete(
+    /// Apply `uuid`'s resolved put/delete update (if the collapsed map has
+    /// one) to an old result `line`, returning the line to write or `None` if
+    /// it should be dropped as deleted.
+    fn resolve_result_line(
         &self,
-        should_update_results: &mut bool,
-        puts_deletes: &mut VecDeque<PutDeleteUpdate>,
-        result_line: &mut ReadResultLine,
-    ) {
-        if *should_update_results {
-            if puts_deletes.is_empty() {
-                // load more put and delete updates
-                match self.cache_number_list.lock().unwrap().pop_front() {
-                    Some(n) => {
-                        let file_name = put_delete_file_name(n);
-                        let file = std::fs::File::open(file_name).unwrap();
-                        let content: Vec<PutDeleteUpdate> =
-                            bincode::deserialize_from(file).unwrap();
-                        puts_deletes.extend(content);
-                    }
-                    None => {
-                        // there is no more put or delete update
-                        *should_update_results = false;
-                    }
-                }
+        puts_deletes: &mut BTreeMap<String, PutDeleteUpdate>,
+        uuid: &str,
+        line: String,
+    ) -> Option<String> {
+        let Some(update) = puts_deletes.remove(uuid) else {
+            // no update was collapsed for this uuid
+            return Some(line);
+        };
+
+        let line_version = line_version(&line);
+        let mut tombstones = self.tombstones.lock().unwrap();
+        let tombstone_ts = tombstones.get(&update.uuid).copied().unwrap_or(0);
+
+        if update.delete {
+            // observed-remove: record the tombstone, but only drop the row if this
+            // delete is not already stale with respect to the row's own version
+            let ts = tombstone_ts.max(update.timestamp);
+            tombstones.insert(update.uuid.clone(), ts);
+            if update.timestamp >= line_version {
+                return None;
             }
+            return Some(line);
+        }
 
-            // apply update here if there is one for this result line
-            if let Some(update) = puts_deletes.pop_front() {
-                if update.uuid != result_line.line.split(',').next().unwrap() {
-                    // push it back to the front if it is not the update we want
-                    puts_deletes.push_front(update);
-                    return;
-                }
+        // this is a put update: a stale tombstone still beats an older put (LWW)
+        if update.timestamp <= tombstone_ts || update.timestamp <= line_version {
+            // the existing row is already at least as new as this put, keep it
+            return Some(line);
+        }
+        drop(tombstones);
 
-                // we have found the update for this read result line
-                result_line.updated = true;
+        // there has to be a put update here so we can just unwrap
+        let put = update.put.unwrap();
 
-                if update.delete {
-                    // this is a delete update
-                    result_line.mark_for_deletion = true;
-                    return;
-                }
+        // construct the parts of the new line, fields 0..=4 plus the new version
+        let likes = put.likes.to_string();
+        let image = match put.image {
+            // chunk the new payload the same way a freshly fetched row would
+            Some(new_image) => super::chunk_store::encode_image_manifest(Some(&new_image)),
+            // there is no update for image, so we should just use the old manifest
+            None => csv::field(&line, 4).unwrap(),
+        };
+        let timestamp = update.timestamp.to_string();
 
-                // this is a put update
-
-                // there has to be a put update here so we can just unwrap
-                let put = update.put.unwrap();
-
-                // construct the parts of the new line
-                let parts: [String; 5] = [
-                    update.uuid,
-                    put.author,
-                    put.message,
-                    put.likes.to_string(),
-                    match put.image {
-                        Some(new_image) => new_image,
-                        // there is no update for image, so we should just use the old image
-                        None => result_line.line.split(',').last().unwrap().to_string(),
-                    },
-                ];
-
-                // replace the old result line with the new updated line
-                result_line.line = parts.join(",");
+        // replace the old result line with the new updated line
+        Some(csv::encode_row(&[
+            &update.uuid,
+            &put.author,
+            &put.message,
+            &likes,
+            &image,
+            &timestamp,
+        ]))
+    }
+}
+
+/// Collapse every put/delete observed during this merge into one resolved
+/// effect per uuid via last-write-wins on `timestamp`: the highest-timestamp
+/// update for that uuid wins outright, whether it's a put or a delete.
+/// Without this, a second update to the same uuid within one merge would
+/// sit unconsumed wherever the stream was walked one operation at a time,
+/// blocking every operation behind it. This is the merge's one and only
+/// per-uuid LWW collapse step; nothing downstream re-resolves ordering.
+fn collapse_puts_deletes(updates: Vec<PutDeleteUpdate>) -> BTreeMap<String, PutDeleteUpdate> {
+    let mut resolved: BTreeMap<String, PutDeleteUpdate> = BTreeMap::new();
+    for update in updates {
+        match resolved.entry(update.uuid.clone()) {
+            Entry::Occupied(mut existing) => {
+                if update.timestamp >= existing.get().timestamp {
+                    existing.insert(update);
+                }
+            }
+            Entry::Vacant(slot) => {
+                slot.insert(update);
             }
         }
     }
+    resolved
 }